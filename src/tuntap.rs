@@ -1,3 +1,4 @@
+use std::error;
 use std::ffi::CString;
 use std::fmt;
 use std::fs::File;
@@ -6,19 +7,169 @@ use std::io::{Read, Write};
 use std::io;
 use std::os::unix::prelude::AsRawFd;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use libc::c_int;
-use libc::consts::os::bsd44::{AF_INET6, SOCK_DGRAM};
+use libc::consts::os::bsd44::{AF_INET, AF_INET6, SOCK_DGRAM};
+use libc::consts::os::extra::O_NONBLOCK;
+use libc::consts::os::posix01::{F_GETFL, F_SETFL};
 use libc::funcs::bsd43::socket;
 use libc::funcs::bsd44::ioctl;
+use libc::funcs::posix88::fcntl::fcntl;
 use libc::funcs::posix88::unistd::close;
-use libc::types::os::common::bsd44::in6_addr;
+use libc::types::os::common::bsd44::{in6_addr, in_addr, sockaddr_in};
 use c_interop::*;
 
 
 const DEVICE_PATH: &'static str = "/dev/net/tun";
 
-// TODO Make not a constant
-const MTU_SIZE: usize = 1500;
+// A tap device hands back an Ethernet frame, so every read carries this much
+// extra header on top of the interface's IP MTU.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+// struct tun_pi { __u16 flags; __be16 proto; }, prepended to every packet
+// unless the device was created with IFF_NO_PI.
+const TUN_PI_HEADER_SIZE: usize = 4;
+
+// struct virtio_net_hdr { u8 flags; u8 gso_type; le16 hdr_len; le16 gso_size;
+// le16 csum_start; le16 csum_offset; }, prepended to every packet when the
+// device was created with IFF_VNET_HDR.
+const VNET_HDR_SIZE: usize = 10;
+
+/// Computes the 4-byte network-order netmask for a `/prefix_len` IPv4
+/// subnet. `prefix_len` must be at most 32; callers are expected to have
+/// validated that already.
+fn ipv4_netmask(prefix_len: u8) -> [u8; 4] {
+	let mask = if prefix_len == 0 { 0u32 } else { !0u32 << (32 - prefix_len as u32) };
+	[
+		(mask >> 24) as u8,
+		(mask >> 16) as u8,
+		(mask >> 8) as u8,
+		mask as u8
+	]
+}
+
+/// Shared by `TunTap::read_with_protocol` and `TunQueue::read_with_protocol`:
+/// reads one packet, stripping the leading `tun_pi` header if present.
+/// Panics if the device was created with `vnet_hdr` — its packets carry a
+/// leading `virtio_net_hdr` this path doesn't know how to strip, so treating
+/// it as payload would silently hand back corrupted data; use
+/// `read_vnet_framed` instead.
+fn read_framed<'a>(file: &mut File, typ: TunTapType, no_pi: bool, vnet_hdr: bool, mtu: usize, buffer: &'a mut [u8]) -> io::Result<(u16, &'a [u8])> {
+	assert!(!vnet_hdr, "device was created with vnet_hdr; use read_with_vnet_header instead");
+
+	let overhead = match typ {
+		TunTapType::Tap => ETHERNET_HEADER_SIZE,
+		TunTapType::Tun => 0
+	};
+	let pi_size = if no_pi { 0 } else { TUN_PI_HEADER_SIZE };
+	assert!(buffer.len() >= mtu + overhead + pi_size);
+
+	let len = try!(file.read(buffer));
+
+	if pi_size == 0 {
+		return Ok((0, &buffer[..len]));
+	}
+
+	assert!(len >= pi_size);
+	let protocol = (buffer[2] as u16) << 8 | buffer[3] as u16;
+	Ok((protocol, &buffer[pi_size..len]))
+}
+
+/// Shared by `TunTap::read_with_vnet_header` and
+/// `TunQueue::read_with_vnet_header`.
+fn read_vnet_framed<'a>(file: &mut File, vnet_hdr: bool, buffer: &'a mut [u8]) -> io::Result<(VnetHeader, &'a [u8])> {
+	assert!(vnet_hdr, "device wasn't created with vnet_hdr");
+
+	let len = try!(file.read(buffer));
+	assert!(len >= VNET_HDR_SIZE);
+
+	let header = VnetHeader::parse(&buffer[..VNET_HDR_SIZE]);
+	Ok((header, &buffer[VNET_HDR_SIZE..len]))
+}
+
+/// Shared by `TunTap::write_with_vnet_header` and
+/// `TunQueue::write_with_vnet_header`.
+fn write_vnet_framed(file: &mut File, vnet_hdr: bool, header: &VnetHeader, data: &[u8]) -> io::Result<()> {
+	assert!(vnet_hdr, "device wasn't created with vnet_hdr");
+
+	let mut framed = vec![0u8; VNET_HDR_SIZE + data.len()];
+	header.write_into(&mut framed[..VNET_HDR_SIZE]);
+	framed[VNET_HDR_SIZE..].clone_from_slice(data);
+
+	file.write_all(&framed)
+}
+
+
+/// Errors returned by the `TunTap` constructors and ioctl wrappers.
+///
+/// This lets callers recover from conditions like a missing `/dev/net/tun`,
+/// `EPERM`, or a name collision instead of having the whole process unwind.
+#[derive(Debug)]
+pub enum Error {
+	/// The requested interface name (including its trailing NUL) didn't fit
+	/// in `IFNAMSIZ` bytes.
+	NameTooLong,
+	/// `vnet_hdr` was requested without `no_pi`. The kernel would then
+	/// prefix every packet with `[virtio_net_hdr][tun_pi]`, which
+	/// `read_with_vnet_header` doesn't account for — it only strips the
+	/// `virtio_net_hdr`, so a leftover 4-byte `tun_pi` header would end up
+	/// glued onto the payload it returns.
+	VnetHdrRequiresNoPi,
+	/// `add_address` was called with a `prefix_len` greater than 32 for a
+	/// 4-byte (IPv4) address.
+	InvalidPrefixLen(u8),
+	/// `add_address` was called with an `ip` that was neither 4 (IPv4) nor
+	/// 16 (IPv6) bytes long.
+	InvalidAddressLength(usize),
+	/// Opening `/dev/net/tun` failed.
+	DeviceOpen(io::Error),
+	/// An `ioctl` call failed; `name` identifies which one.
+	Ioctl { name: &'static str, cause: io::Error },
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::NameTooLong => write!(f, "interface name too long, max length is {}", IFNAMSIZ - 1),
+			Error::VnetHdrRequiresNoPi => write!(f, "vnet_hdr requires no_pi, otherwise read_with_vnet_header can't tell where the tun_pi header ends"),
+			Error::InvalidPrefixLen(prefix_len) => write!(f, "prefix_len {} is invalid for an IPv4 address, must be at most 32", prefix_len),
+			Error::InvalidAddressLength(len) => write!(f, "IP address must be either 4 or 16 bytes, got {}", len),
+			Error::DeviceOpen(ref cause) => write!(f, "couldn't open tun device '{}': {}", DEVICE_PATH, cause),
+			Error::Ioctl { name, ref cause } => write!(f, "ioctl({}) failed: {}", name, cause)
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn description(&self) -> &str {
+		match *self {
+			Error::NameTooLong => "interface name too long",
+			Error::VnetHdrRequiresNoPi => "vnet_hdr requires no_pi",
+			Error::InvalidPrefixLen(_) => "invalid IPv4 prefix_len",
+			Error::InvalidAddressLength(_) => "invalid IP address length",
+			Error::DeviceOpen(_) => "couldn't open tun device",
+			Error::Ioctl { .. } => "ioctl failed"
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::NameTooLong => None,
+			Error::VnetHdrRequiresNoPi => None,
+			Error::InvalidPrefixLen(_) => None,
+			Error::InvalidAddressLength(_) => None,
+			Error::DeviceOpen(ref cause) => Some(cause),
+			Error::Ioctl { ref cause, .. } => Some(cause)
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::DeviceOpen(err)
+	}
+}
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -28,11 +179,77 @@ pub enum TunTapType {
 }
 
 
+/// Whether the kernel currently reports the interface as up and running.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LinkState {
+	Up,
+	Down
+}
+
+
+/// Static properties of a device, reported to a single-threaded event loop
+/// driving it through `Driver`.
+#[derive(Debug, Copy, Clone)]
+pub struct Capabilities {
+	pub max_transmission_unit: usize
+}
+
+
+/// The `virtio_net_hdr` a device created with `vnet_hdr` prepends to every
+/// packet, carrying GSO and checksum-offload metadata so a large TCP send
+/// can arrive as one GSO-batched segment instead of being pre-split to the
+/// MTU in software.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VnetHeader {
+	pub flags: u8,
+	pub gso_type: u8,
+	pub hdr_len: u16,
+	pub gso_size: u16,
+	pub csum_start: u16,
+	pub csum_offset: u16
+}
+
+impl VnetHeader {
+	fn parse(bytes: &[u8]) -> VnetHeader {
+		VnetHeader {
+			flags: bytes[0],
+			gso_type: bytes[1],
+			hdr_len: (bytes[3] as u16) << 8 | bytes[2] as u16,
+			gso_size: (bytes[5] as u16) << 8 | bytes[4] as u16,
+			csum_start: (bytes[7] as u16) << 8 | bytes[6] as u16,
+			csum_offset: (bytes[9] as u16) << 8 | bytes[8] as u16
+		}
+	}
+
+	fn write_into(&self, bytes: &mut [u8]) {
+		bytes[0] = self.flags;
+		bytes[1] = self.gso_type;
+		bytes[2] = self.hdr_len as u8;
+		bytes[3] = (self.hdr_len >> 8) as u8;
+		bytes[4] = self.gso_size as u8;
+		bytes[5] = (self.gso_size >> 8) as u8;
+		bytes[6] = self.csum_start as u8;
+		bytes[7] = (self.csum_start >> 8) as u8;
+		bytes[8] = self.csum_offset as u8;
+		bytes[9] = (self.csum_offset >> 8) as u8;
+	}
+}
+
+
 pub struct TunTap {
 	pub file: File,
+	typ: TunTapType,
+	no_pi: bool,
+	vnet_hdr: bool,
 	sock: c_int,
 	if_name: [u8; IFNAMSIZ],
-	if_index: c_int
+	if_index: c_int,
+	// Cached so read/read_with_protocol don't pay for a SIOCGIFMTU ioctl on
+	// every packet; kept in sync by set_mtu. get_mtu() itself still queries
+	// the kernel live. Shared (not cloned) with every TunQueue opened
+	// against this interface, so set_mtu updates their cache too instead of
+	// leaving them stuck with the value from when they were opened.
+	mtu: Arc<AtomicUsize>
 }
 
 impl Drop for TunTap {
@@ -49,60 +266,187 @@ impl fmt::Debug for TunTap {
 
 
 impl TunTap {
-	pub fn create(typ: TunTapType) -> TunTap {
-		TunTap::create_named(typ, &CString::from_slice(&[]))
+	/// Creates a device with a kernel-chosen name (an empty name is passed
+	/// to `create_named`, so the kernel picks `tun%d` or `tap%d` by type)
+	/// with `IFF_NO_PI` set, so `read` hands back a clean packet with no
+	/// leading `tun_pi` header.
+	pub fn create(typ: TunTapType) -> Result<TunTap, Error> {
+		TunTap::create_named(typ, &CString::from_slice(&[]), true, false)
 	}
 
-	pub fn create_named(typ: TunTapType, name: &CString) -> TunTap {
-		let (file, if_name) = TunTap::create_if(typ, name);
-		let (sock, if_index) = TunTap::create_socket(if_name);
+	/// Creates a device named `name`. `name` may contain a single `%d`
+	/// (e.g. `"tun%d"`), in which case the kernel substitutes the lowest
+	/// free index instead of failing on a collision. Use `get_name()` on
+	/// the returned `TunTap` to read back the name the kernel actually
+	/// assigned.
+	///
+	/// When `no_pi` is true, `IFF_NO_PI` is set and the kernel omits the
+	/// 4-byte `tun_pi` header from every packet; `read` then returns the
+	/// payload as-is. When false, the header is present and `read` strips
+	/// it automatically, while `read_with_protocol` exposes the EtherType
+	/// it carried.
+	///
+	/// When `vnet_hdr` is true, `IFF_VNET_HDR` is set and every packet
+	/// carries a leading `virtio_net_hdr`; read it with
+	/// `read_with_vnet_header` and write it with `write_with_vnet_header`
+	/// instead of the plain `read`/`write`. Combine with `set_offload` to
+	/// negotiate the GSO/checksum offloads the header describes. `vnet_hdr`
+	/// requires `no_pi`: otherwise the kernel also glues a `tun_pi` header
+	/// onto every packet, which `read_with_vnet_header` doesn't strip.
+	pub fn create_named(typ: TunTapType, name: &CString, no_pi: bool, vnet_hdr: bool) -> Result<TunTap, Error> {
+		let (file, if_name) = try!(TunTap::create_if(typ, name, no_pi, false, vnet_hdr));
+		let (sock, if_index) = try!(TunTap::create_socket(if_name));
+		let mtu = try!(TunTap::query_mtu(sock, if_name).map_err(|e| Error::Ioctl { name: "SIOCGIFMTU", cause: e }));
+
+		Ok(TunTap {
+			file: file,
+			typ: typ,
+			no_pi: no_pi,
+			vnet_hdr: vnet_hdr,
+			sock: sock,
+			if_name: if_name,
+			if_index: if_index,
+			mtu: Arc::new(AtomicUsize::new(mtu))
+		})
+	}
 
-		TunTap {
+	/// Creates a device together with `num_queues` additional queue file
+	/// descriptors attached to it, so a multi-threaded forwarder can read
+	/// and write each queue from its own thread without contending on a
+	/// single `File`. The returned `TunTap` is itself usable as the first
+	/// queue; each `TunQueue` can later be taken out of service with
+	/// `detach` and put back with `attach`.
+	pub fn create_multi_queue(typ: TunTapType, name: &CString, no_pi: bool, vnet_hdr: bool, num_queues: usize) -> Result<(TunTap, Vec<TunQueue>), Error> {
+		let (file, if_name) = try!(TunTap::create_if(typ, name, no_pi, true, vnet_hdr));
+		let (sock, if_index) = try!(TunTap::create_socket(if_name));
+		let mtu = try!(TunTap::query_mtu(sock, if_name).map_err(|e| Error::Ioctl { name: "SIOCGIFMTU", cause: e }));
+		let mtu = Arc::new(AtomicUsize::new(mtu));
+
+		let tuntap = TunTap {
 			file: file,
+			typ: typ,
+			no_pi: no_pi,
+			vnet_hdr: vnet_hdr,
 			sock: sock,
 			if_name: if_name,
-			if_index: if_index
+			if_index: if_index,
+			mtu: mtu.clone()
+		};
+
+		let mut queues = Vec::with_capacity(num_queues);
+		for _ in 0..num_queues {
+			queues.push(try!(TunTap::open_queue(typ, if_name, no_pi, vnet_hdr, mtu.clone())));
 		}
+
+		Ok((tuntap, queues))
 	}
 
-	fn create_if(typ: TunTapType, name: &CString) -> (File, [u8; IFNAMSIZ]) {
+	fn create_if(typ: TunTapType, name: &CString, no_pi: bool, multi_queue: bool, vnet_hdr: bool) -> Result<(File, [u8; IFNAMSIZ]), Error> {
 		let name_slice = name.as_bytes_with_nul();
 		if name_slice.len() > IFNAMSIZ {
-			panic!("Interface name too long, max length is {}", IFNAMSIZ - 1);
+			return Err(Error::NameTooLong);
+		}
+		if vnet_hdr && !no_pi {
+			return Err(Error::VnetHdrRequiresNoPi);
 		}
 
 		let path = Path::new(DEVICE_PATH);
 		let file = match OpenOptions::new().read(true).write(true).open(&path) {
-			Err(why) => panic!("Couldn't open tun device '{}': {:?}", path.display(), why),
+			Err(why) => return Err(Error::DeviceOpen(why)),
 			Ok(file) => file,
 		};
 
+		let flags = TunTap::open_flags(typ, no_pi, multi_queue, vnet_hdr);
+
 		let mut req = ioctl_flags_data {
 			ifr_name: {
 				let mut buffer = [0u8; IFNAMSIZ];
 				buffer.clone_from_slice(name_slice);
 				buffer
 			},
-			ifr_flags: match typ {
-				TunTapType::Tun => IFF_TUN,
-				TunTapType::Tap => IFF_TAP
-			}
+			ifr_flags: flags
 		};
 
 		let res = unsafe { ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) };
 		if res < 0 {
-			panic!("{}", io::Error::last_os_error());
+			return Err(Error::Ioctl { name: "TUNSETIFF", cause: io::Error::last_os_error() });
 		}
 
-		(file, req.ifr_name)
+		if vnet_hdr {
+			try!(TunTap::set_vnet_hdr_size(&file));
+		}
+
+		Ok((file, req.ifr_name))
 	}
 
-	fn create_socket(if_name: [u8; IFNAMSIZ]) -> (c_int, c_int) {
+	/// Opens an additional queue fd against an already-created
+	/// `IFF_MULTI_QUEUE` interface, attaching it as an extra queue via a
+	/// second `TUNSETIFF` carrying the same `ifr_name`.
+	fn open_queue(typ: TunTapType, if_name: [u8; IFNAMSIZ], no_pi: bool, vnet_hdr: bool, mtu: Arc<AtomicUsize>) -> Result<TunQueue, Error> {
+		let path = Path::new(DEVICE_PATH);
+		let file = match OpenOptions::new().read(true).write(true).open(&path) {
+			Err(why) => return Err(Error::DeviceOpen(why)),
+			Ok(file) => file,
+		};
+
+		let mut req = ioctl_flags_data {
+			ifr_name: if_name,
+			ifr_flags: TunTap::open_flags(typ, no_pi, true, vnet_hdr)
+		};
+
+		let res = unsafe { ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) };
+		if res < 0 {
+			return Err(Error::Ioctl { name: "TUNSETIFF", cause: io::Error::last_os_error() });
+		}
+
+		if vnet_hdr {
+			try!(TunTap::set_vnet_hdr_size(&file));
+		}
+
+		Ok(TunQueue {
+			file: file,
+			typ: typ,
+			no_pi: no_pi,
+			vnet_hdr: vnet_hdr,
+			if_name: req.ifr_name,
+			mtu: mtu
+		})
+	}
+
+	fn set_vnet_hdr_size(file: &File) -> Result<(), Error> {
+		let mut size: c_int = VNET_HDR_SIZE as c_int;
+
+		let res = unsafe { ioctl(file.as_raw_fd(), TUNSETVNETHDRSZ, &mut size) };
+		if res < 0 {
+			return Err(Error::Ioctl { name: "TUNSETVNETHDRSZ", cause: io::Error::last_os_error() });
+		}
+
+		Ok(())
+	}
+
+	fn open_flags(typ: TunTapType, no_pi: bool, multi_queue: bool, vnet_hdr: bool) -> c_int {
+		let mut flags = match typ {
+			TunTapType::Tun => IFF_TUN,
+			TunTapType::Tap => IFF_TAP
+		};
+		if no_pi {
+			flags |= IFF_NO_PI;
+		}
+		if multi_queue {
+			flags |= IFF_MULTI_QUEUE;
+		}
+		if vnet_hdr {
+			flags |= IFF_VNET_HDR;
+		}
+		flags
+	}
+
+	fn create_socket(if_name: [u8; IFNAMSIZ]) -> Result<(c_int, c_int), Error> {
 		let sock = unsafe { socket(AF_INET6, SOCK_DGRAM, 0) };
 		if sock < 0 {
-			panic!("{}", io::Error::last_os_error());
+			return Err(Error::Ioctl { name: "socket", cause: io::Error::last_os_error() });
 		}
-		
+
 		let mut req = ioctl_ifindex_data {
 			ifr_name: if_name,
 			ifr_ifindex: -1
@@ -112,12 +456,14 @@ impl TunTap {
 		if res < 0 {
 			let err = io::Error::last_os_error();
 			unsafe { close(sock) };
-			panic!("{}", err);
+			return Err(Error::Ioctl { name: "SIOCGIFINDEX", cause: err });
 		}
 
-		(sock, req.ifr_ifindex)
+		Ok((sock, req.ifr_ifindex))
 	}
 
+	/// Returns the name the kernel assigned this device, which may differ
+	/// from the `name` passed to `create_named` if it contained `%d`.
 	pub fn get_name(&self) -> CString {
 		let nul_pos = match self.if_name.as_slice().position_elem(&0) {
 			Some(p) => p,
@@ -127,36 +473,97 @@ impl TunTap {
 		CString::from_slice(&self.if_name[..nul_pos])
 	}
 
-	pub fn up(&self) {
+	pub fn up(&self) -> Result<(), Error> {
+		let flags = try!(self.get_flags());
+
+		if flags & (IFF_UP | IFF_RUNNING) == (IFF_UP | IFF_RUNNING) {
+			// Already up
+			return Ok(());
+		}
+
+		self.set_flags(flags | IFF_UP | IFF_RUNNING)
+	}
+
+	/// Reports whether the kernel currently has the interface's `IFF_UP`
+	/// and `IFF_RUNNING` flags set.
+	pub fn link_state(&self) -> Result<LinkState, Error> {
+		let flags = try!(self.get_flags());
+
+		if flags & (IFF_UP | IFF_RUNNING) == (IFF_UP | IFF_RUNNING) {
+			Ok(LinkState::Up)
+		}
+		else {
+			Ok(LinkState::Down)
+		}
+	}
+
+	fn get_flags(&self) -> Result<c_int, Error> {
 		let mut req = ioctl_flags_data {
 			ifr_name: self.if_name,
 			ifr_flags: 0
 		};
 
-
 		let res = unsafe { ioctl(self.sock, SIOCGIFFLAGS, &mut req) };
 		if res < 0 {
-			panic!("{}", io::Error::last_os_error());
+			return Err(Error::Ioctl { name: "SIOCGIFFLAGS", cause: io::Error::last_os_error() });
 		}
 
-		if req.ifr_flags & IFF_UP & IFF_RUNNING != 0 {
-			// Already up
-			return;
-		}
+		Ok(req.ifr_flags)
+	}
 
-		req.ifr_flags |= IFF_UP | IFF_RUNNING;
+	fn set_flags(&self, flags: c_int) -> Result<(), Error> {
+		let mut req = ioctl_flags_data {
+			ifr_name: self.if_name,
+			ifr_flags: flags
+		};
 
 		let res = unsafe { ioctl(self.sock, SIOCSIFFLAGS, &mut req) };
 		if res < 0 {
-			panic!("{}", io::Error::last_os_error());
+			return Err(Error::Ioctl { name: "SIOCSIFFLAGS", cause: io::Error::last_os_error() });
 		}
+
+		Ok(())
 	}
 
-	pub fn add_address(&self, ip: &[u8]) {
-		self.up();
+	pub fn add_address(&self, ip: &[u8], prefix_len: u8) -> Result<(), Error> {
+		try!(self.up());
 
 		if ip.len() == 4 {
-			panic!("IPv4 not implemented");
+			if prefix_len > 32 {
+				return Err(Error::InvalidPrefixLen(prefix_len));
+			}
+
+			let addr = sockaddr_in {
+				sin_family: AF_INET as u16,
+				sin_port: 0,
+				sin_addr: in_addr { s_addr:
+					(ip[3] as u32) << 24 | (ip[2] as u32) << 16 | (ip[1] as u32) << 8 | ip[0] as u32
+				},
+				sin_zero: [0; 8]
+			};
+
+			let mut req = ioctl_addr_data {
+				ifr_name: self.if_name,
+				ifr_addr: addr
+			};
+
+			let res = unsafe { ioctl(self.sock, SIOCSIFADDR, &mut req) };
+			if res < 0 {
+				return Err(Error::Ioctl { name: "SIOCSIFADDR", cause: io::Error::last_os_error() });
+			}
+
+			let mask_bytes = ipv4_netmask(prefix_len);
+
+			req.ifr_addr.sin_addr = in_addr { s_addr:
+				(mask_bytes[3] as u32) << 24 | (mask_bytes[2] as u32) << 16 | (mask_bytes[1] as u32) << 8 | mask_bytes[0] as u32
+			};
+
+			let res = unsafe { ioctl(self.sock, SIOCSIFNETMASK, &mut req) };
+			if res < 0 {
+				return Err(Error::Ioctl { name: "SIOCSIFNETMASK", cause: io::Error::last_os_error() });
+			}
+
+			Ok(())
 		}
 		else if ip.len() == 16 {
 			let mut req = in6_ifreq {
@@ -170,28 +577,297 @@ impl TunTap {
 					(ip[13] as u16) << 8 | ip[12] as u16,
 					(ip[15] as u16) << 8 | ip[14] as u16
 				]},
-				ifr6_prefixlen: 8,
+				ifr6_prefixlen: prefix_len,
 				ifr6_ifindex: self.if_index
 			};
 
 			let res = unsafe { ioctl(self.sock, SIOCSIFADDR, &mut req) };
 			if res < 0 {
-				panic!("{}", io::Error::last_os_error());
+				return Err(Error::Ioctl { name: "SIOCSIFADDR", cause: io::Error::last_os_error() });
 			}
+
+			Ok(())
 		}
 		else {
-			panic!("IP length must be either 4 or 16 bytes, got {}", ip.len());
+			Err(Error::InvalidAddressLength(ip.len()))
+		}
+	}
+
+	/// Queries the kernel for the interface's current MTU. Always issues a
+	/// fresh `SIOCGIFMTU` ioctl; `read`/`read_with_protocol` use the
+	/// cached value instead so the hot path doesn't pay for a syscall on
+	/// every packet.
+	pub fn get_mtu(&self) -> io::Result<usize> {
+		TunTap::query_mtu(self.sock, self.if_name)
+	}
+
+	fn query_mtu(sock: c_int, if_name: [u8; IFNAMSIZ]) -> io::Result<usize> {
+		let mut req = ioctl_mtu_data {
+			ifr_name: if_name,
+			ifr_mtu: 0
+		};
+
+		let res = unsafe { ioctl(sock, SIOCGIFMTU, &mut req) };
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(req.ifr_mtu as usize)
+	}
+
+	/// Sets the interface's MTU via `SIOCSIFMTU`. Since every `TunQueue`
+	/// opened against this interface shares the same cache (see the `mtu`
+	/// field), this also keeps their `read`/`read_with_protocol` buffer
+	/// checks in sync — they don't need their own `set_mtu`.
+	pub fn set_mtu(&self, mtu: usize) -> io::Result<()> {
+		let mut req = ioctl_mtu_data {
+			ifr_name: self.if_name,
+			ifr_mtu: mtu as c_int
+		};
+
+		let res = unsafe { ioctl(self.sock, SIOCSIFMTU, &mut req) };
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		self.mtu.store(mtu, Ordering::SeqCst);
+		Ok(())
+	}
+
+	/// Reads a packet, stripping the leading `tun_pi` header if the device
+	/// wasn't created with `no_pi`. Callers that need the protocol the
+	/// header carried should use `read_with_protocol` instead.
+	pub fn read<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<&'a [u8]> {
+		let (_, payload) = try!(self.read_with_protocol(buffer));
+		Ok(payload)
+	}
+
+	/// Reads a packet and, when the device wasn't created with `no_pi`,
+	/// returns the EtherType/protocol carried by its `tun_pi` header
+	/// alongside the payload with that header stripped off. Devices
+	/// created with `no_pi` always report protocol `0`.
+	pub fn read_with_protocol<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(u16, &'a [u8])> {
+		read_framed(&mut self.file, self.typ, self.no_pi, self.vnet_hdr, self.mtu.load(Ordering::SeqCst), buffer)
+	}
+
+	/// Writes `data` as-is. Panics if the device was created with
+	/// `vnet_hdr` — use `write_with_vnet_header` instead, since the kernel
+	/// expects every write to carry a leading `virtio_net_hdr` in that mode.
+	pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+		assert!(!self.vnet_hdr, "device was created with vnet_hdr; use write_with_vnet_header instead");
+		self.file.write_all(data)
+	}
+
+	/// Reads a packet from a device created with `vnet_hdr`, returning the
+	/// `virtio_net_hdr` it carried alongside the payload with that header
+	/// stripped off.
+	pub fn read_with_vnet_header<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(VnetHeader, &'a [u8])> {
+		read_vnet_framed(&mut self.file, self.vnet_hdr, buffer)
+	}
+
+	/// Writes `data` to a device created with `vnet_hdr`, prefixed with
+	/// `header`.
+	pub fn write_with_vnet_header(&mut self, header: &VnetHeader, data: &[u8]) -> io::Result<()> {
+		write_vnet_framed(&mut self.file, self.vnet_hdr, header, data)
+	}
+
+	/// Negotiates kernel offloads via `TUNSETOFFLOAD`, e.g.
+	/// `TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_UFO`. Only meaningful
+	/// on a device created with `vnet_hdr`.
+	pub fn set_offload(&self, flags: c_int) -> io::Result<()> {
+		let res = unsafe { ioctl(self.file.as_raw_fd(), TUNSETOFFLOAD, flags) };
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(())
+	}
+
+	/// Sets `O_NONBLOCK` on the device's fd, so `read`/`write` return
+	/// `WouldBlock` instead of parking the calling thread. Combine with
+	/// `as_raw_fd()` to register the device with epoll/mio/async runtimes.
+	pub fn set_nonblocking(&self) -> io::Result<()> {
+		let fd = self.file.as_raw_fd();
+
+		let flags = unsafe { fcntl(fd, F_GETFL, 0) };
+		if flags < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let res = unsafe { fcntl(fd, F_SETFL, flags | O_NONBLOCK) };
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(())
+	}
+}
+
+impl AsRawFd for TunTap {
+	fn as_raw_fd(&self) -> c_int {
+		self.file.as_raw_fd()
+	}
+}
+
+
+/// A non-blocking adapter over a `TunTap`, mirroring the shape of embassy's
+/// tun driver so the device can be driven from a single-threaded event loop.
+pub struct Driver {
+	tuntap: TunTap
+}
+
+impl Driver {
+	/// Wraps `tuntap`, putting it into non-blocking mode.
+	pub fn new(tuntap: TunTap) -> io::Result<Driver> {
+		try!(tuntap.set_nonblocking());
+		Ok(Driver { tuntap: tuntap })
+	}
+
+	pub fn capabilities(&self) -> io::Result<Capabilities> {
+		let mtu = try!(self.tuntap.get_mtu());
+		Ok(Capabilities { max_transmission_unit: mtu })
+	}
+
+	pub fn link_state(&self) -> io::Result<LinkState> {
+		match self.tuntap.link_state() {
+			Ok(state) => Ok(state),
+			Err(Error::Ioctl { cause, .. }) => Err(cause),
+			Err(other) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", other)))
 		}
 	}
 
+	/// Reads a packet without blocking, returning `Err` with
+	/// `ErrorKind::WouldBlock` if none is available yet.
+	pub fn receive<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<&'a [u8]> {
+		self.tuntap.read(buffer)
+	}
+
+	/// Writes a packet without blocking, returning `Err` with
+	/// `ErrorKind::WouldBlock` if the device's queue is full.
+	pub fn transmit(&mut self, data: &[u8]) -> io::Result<()> {
+		self.tuntap.write(data)
+	}
+}
+
+
+/// An additional queue fd attached to a `TunTap` created via
+/// `create_multi_queue`, usable from its own thread for parallel I/O.
+pub struct TunQueue {
+	pub file: File,
+	typ: TunTapType,
+	no_pi: bool,
+	vnet_hdr: bool,
+	if_name: [u8; IFNAMSIZ],
+	// Shared with the TunTap this queue was opened from, so TunTap::set_mtu
+	// keeps this in sync instead of it going stale after a resize.
+	mtu: Arc<AtomicUsize>
+}
+
+impl TunQueue {
+	/// Re-attaches this queue after a `detach`, via `TUNSETQUEUE`.
+	pub fn attach(&self) -> io::Result<()> {
+		self.set_queue_state(IFF_ATTACH_QUEUE)
+	}
+
+	/// Takes this queue out of service via `TUNSETQUEUE`, without closing
+	/// its fd, so it can be `attach`ed again later.
+	pub fn detach(&self) -> io::Result<()> {
+		self.set_queue_state(IFF_DETACH_QUEUE)
+	}
+
+	fn set_queue_state(&self, state: c_int) -> io::Result<()> {
+		let mut req = ioctl_flags_data {
+			ifr_name: self.if_name,
+			ifr_flags: state
+		};
+
+		let res = unsafe { ioctl(self.file.as_raw_fd(), TUNSETQUEUE, &mut req) };
+		if res < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(())
+	}
+
+	/// Reads a packet, stripping the leading `tun_pi` header if the queue
+	/// wasn't created with `no_pi`. Mirrors `TunTap::read`.
 	pub fn read<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<&'a [u8]> {
-		assert!(buffer.len() >= MTU_SIZE);
+		let (_, payload) = try!(self.read_with_protocol(buffer));
+		Ok(payload)
+	}
 
-		let len = try!(self.file.read(buffer));
-		Ok(&buffer[..len])
+	/// Mirrors `TunTap::read_with_protocol`.
+	pub fn read_with_protocol<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(u16, &'a [u8])> {
+		read_framed(&mut self.file, self.typ, self.no_pi, self.vnet_hdr, self.mtu.load(Ordering::SeqCst), buffer)
 	}
 
+	/// Mirrors `TunTap::write`.
 	pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+		assert!(!self.vnet_hdr, "queue was created with vnet_hdr; use write_with_vnet_header instead");
 		self.file.write_all(data)
 	}
+
+	/// Mirrors `TunTap::read_with_vnet_header`.
+	pub fn read_with_vnet_header<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(VnetHeader, &'a [u8])> {
+		read_vnet_framed(&mut self.file, self.vnet_hdr, buffer)
+	}
+
+	/// Mirrors `TunTap::write_with_vnet_header`.
+	pub fn write_with_vnet_header(&mut self, header: &VnetHeader, data: &[u8]) -> io::Result<()> {
+		write_vnet_framed(&mut self.file, self.vnet_hdr, header, data)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ipv4_netmask_common_prefixes() {
+		assert_eq!(ipv4_netmask(24), [255, 255, 255, 0]);
+		assert_eq!(ipv4_netmask(16), [255, 255, 0, 0]);
+		assert_eq!(ipv4_netmask(8), [255, 0, 0, 0]);
+		assert_eq!(ipv4_netmask(32), [255, 255, 255, 255]);
+		assert_eq!(ipv4_netmask(0), [0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn vnet_header_round_trip() {
+		let header = VnetHeader {
+			flags: 1,
+			gso_type: 4,
+			hdr_len: 0x1234,
+			gso_size: 0x5678,
+			csum_start: 0x9abc,
+			csum_offset: 0xdef0
+		};
+
+		let mut bytes = [0u8; VNET_HDR_SIZE];
+		header.write_into(&mut bytes);
+
+		assert_eq!(VnetHeader::parse(&bytes), header);
+	}
+
+	// create_if passes whatever name the kernel substituted for a trailing
+	// "%d" back in ifr_name, and get_name() just reads that buffer; this
+	// exercises the readback half of that without needing a real device.
+	#[test]
+	fn get_name_reads_back_kernel_assigned_name() {
+		let mut if_name = [0u8; IFNAMSIZ];
+		if_name[..4].clone_from_slice(b"tap3");
+
+		let tuntap = TunTap {
+			file: File::open("/dev/null").unwrap(),
+			typ: TunTapType::Tap,
+			no_pi: true,
+			vnet_hdr: false,
+			sock: -1,
+			if_name: if_name,
+			if_index: 0,
+			mtu: Arc::new(AtomicUsize::new(0))
+		};
+
+		assert_eq!(tuntap.get_name().as_bytes(), b"tap3");
+	}
 }